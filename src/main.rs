@@ -1,12 +1,46 @@
 // https://flownet.com/ron/papers/lisp-java/instructions.html
 
+use num_bigint::BigUint;
 use std::collections::HashMap;
-use std::collections::VecDeque;
 use std::env::args;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, BufWriter, Write};
 
-type WordKey = u128;
+// Most words/numbers are short enough to fit in a `u128`, so we stay on that
+// fast path and only promote to an arbitrary-precision `BigUint` when a run
+// of digits is long enough to overflow it (Prechelt's original problem
+// allows numbers of any length).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum WordKey {
+    Small(u128),
+    Big(BigUint),
+}
+
+impl WordKey {
+    fn push_digit(self, digit: u8) -> WordKey {
+        match self {
+            WordKey::Small(acc) => match acc
+                .checked_mul(10)
+                .and_then(|acc| acc.checked_add(digit as u128))
+            {
+                Some(next) => WordKey::Small(next),
+                None => WordKey::Big(BigUint::from(acc) * 10u32 + digit),
+            },
+            WordKey::Big(acc) => WordKey::Big(acc * 10u32 + digit),
+        }
+    }
+}
+
+impl Default for WordKey {
+    fn default() -> WordKey {
+        WordKey::Small(0)
+    }
+}
+
+// Fold a run of digits (0-9) into a WordKey, promoting to BigUint on overflow.
+fn fold_key<I: IntoIterator<Item = u8>>(digits: I) -> WordKey {
+    digits.into_iter().fold(WordKey::default(), WordKey::push_digit)
+}
 
 type Dictionary = HashMap<WordKey, Vec<String>>;
 
@@ -16,11 +50,97 @@ fn read_lines(file: &str) -> impl Iterator<Item = String> {
     BufReader::new(f).lines().map(Result::unwrap)
 }
 
-fn load_dictionary(path: &str) -> Dictionary {
+// The built-in German keypad mapping from Prechelt's original problem, one
+// line of letters per digit 0-9.
+const DEFAULT_MAPPING: [&str; 10] = [
+    "e", "jnq", "rwx", "dsy", "ft", "am", "civ", "bku", "lop", "ghz",
+];
+
+// Maps letters to keypad digits.  Case-insensitive: loading a letter maps
+// both its upper- and lower-case forms.
+struct MappingTable {
+    digit_for_char: [Option<u8>; 128],
+}
+
+impl MappingTable {
+    fn default_table() -> MappingTable {
+        MappingTable::from_lines(DEFAULT_MAPPING.iter().map(|s| s.to_string()))
+            .expect("built-in default mapping table is valid")
+    }
+
+    // Parse a mapping table with one line per digit 0-9, each listing the
+    // letters that map to that digit.  Rejects tables where a letter is
+    // mapped to more than one digit, since that would make word_key
+    // ambiguous.
+    fn from_lines<I: IntoIterator<Item = String>>(lines: I) -> Result<MappingTable, String> {
+        let mut table = MappingTable {
+            digit_for_char: [None; 128],
+        };
+
+        let mut line_count: usize = 0;
+
+        for (line_no, line) in lines.into_iter().enumerate() {
+            if line_no > 9 {
+                return Err("mapping table must have exactly 10 lines, one per digit".to_string());
+            }
+
+            let digit = line_no as u8;
+
+            for ch in line.chars().filter(|c| !c.is_whitespace()) {
+                if !ch.is_ascii_alphabetic() {
+                    return Err(format!(
+                        "mapping table line {} contains non-letter character '{}'",
+                        digit, ch
+                    ));
+                }
+
+                table.map_letter(ch, digit)?;
+            }
+
+            line_count += 1;
+        }
+
+        if line_count != 10 {
+            return Err("mapping table must have exactly 10 lines, one per digit".to_string());
+        }
+
+        Ok(table)
+    }
+
+    fn map_letter(&mut self, ch: char, digit: u8) -> Result<(), String> {
+        for variant in [ch.to_ascii_lowercase(), ch.to_ascii_uppercase()] {
+            if let Some(existing) = self.digit_for_char[variant as usize] {
+                return Err(format!(
+                    "letter '{}' is mapped more than once ({} and {})",
+                    variant, existing, digit
+                ));
+            }
+
+            self.digit_for_char[variant as usize] = Some(digit);
+        }
+
+        Ok(())
+    }
+
+    fn digit_for(&self, ch: char) -> Option<u8> {
+        if ch.is_ascii() {
+            self.digit_for_char[ch as usize]
+        } else {
+            None
+        }
+    }
+}
+
+fn load_mapping_table(path: &str) -> MappingTable {
+    MappingTable::from_lines(read_lines(path))
+        .unwrap_or_else(|e| panic!("invalid mapping table in {}: {}", path, e))
+}
+
+fn load_dictionary(path: &str, mapping: &MappingTable) -> Dictionary {
     let mut result = HashMap::with_capacity(100000);
 
     for w in read_lines(path) {
-        let key = word_key(&w);
+        let key = word_key(&w, mapping);
 
         let entry = result.entry(key).or_insert_with(|| Vec::with_capacity(2));
         entry.push(w.to_string());
@@ -29,39 +149,28 @@ fn load_dictionary(path: &str) -> Dictionary {
     result
 }
 
-// Map our string of letters into a Vec of their corresponding numbers.
-fn word_key(s: &str) -> WordKey {
-    s.chars()
-        .map(|ch| match ch {
-            'e' | 'E' => Some(0),
-            'j' | 'n' | 'q' | 'J' | 'N' | 'Q' => Some(1),
-            'r' | 'w' | 'x' | 'R' | 'W' | 'X' => Some(2),
-            'd' | 's' | 'y' | 'D' | 'S' | 'Y' => Some(3),
-            'f' | 't' | 'F' | 'T' => Some(4),
-            'a' | 'm' | 'A' | 'M' => Some(5),
-            'c' | 'i' | 'v' | 'C' | 'I' | 'V' => Some(6),
-            'b' | 'k' | 'u' | 'B' | 'K' | 'U' => Some(7),
-            'l' | 'o' | 'p' | 'L' | 'O' | 'P' => Some(8),
-            'g' | 'h' | 'z' | 'G' | 'H' | 'Z' => Some(9),
-            _ => None,
-        })
-        .flatten()
-        .fold(0, |acc, n| (acc * 10) + n)
+// Map our string of letters into a WordKey of their corresponding numbers.
+fn word_key(s: &str, mapping: &MappingTable) -> WordKey {
+    fold_key(s.chars().filter_map(|ch| mapping.digit_for(ch)))
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 enum PositionOrLiteral {
     Position(usize),
     Literal(u8),
 }
 
-struct Candidate {
-    input_position: usize,
-    word_end_positions_found: Vec<PositionOrLiteral>,
+// A single position in an encoding: either a dictionary word (one of possibly
+// several choices) or a literal digit carried through from the input.
+#[derive(Clone)]
+enum Slot {
+    Word(Vec<String>),
+    Literal(u8),
 }
 
 struct ExpansionNode {
     words: Vec<String>,
+    digit: Option<u8>,
     next_idx: usize,
     just_wrapped: bool,
 }
@@ -86,145 +195,310 @@ impl ExpansionNode {
     }
 }
 
-// Print expansions by generating every possible combination of words in each of
-// our positions.  Works much like incrementing a number: start from the right
-// and increment each digit.  If it overflows, keep moving left and incrementing
-// until you find a number that doesn't.
-fn print_expansions(writer: &mut dyn Write, number: &str, words: Vec<Vec<String>>) {
-    let mut nodes: Vec<ExpansionNode> = words
-        .into_iter()
-        .map(|w| ExpansionNode {
-            words: w,
-            next_idx: 0,
-            just_wrapped: false,
-        })
-        .collect();
+impl From<Slot> for ExpansionNode {
+    fn from(slot: Slot) -> ExpansionNode {
+        match slot {
+            Slot::Word(words) => ExpansionNode {
+                words,
+                digit: None,
+                next_idx: 0,
+                just_wrapped: false,
+            },
+            Slot::Literal(d) => ExpansionNode {
+                words: vec![d.to_string()],
+                digit: Some(d),
+                next_idx: 0,
+                just_wrapped: false,
+            },
+        }
+    }
+}
+
+// Walk every possible combination of words across a set of slots.  Works much
+// like incrementing a number: start from the right and increment each digit.
+// If it overflows, keep moving left and incrementing until you find a number
+// that doesn't.
+fn for_each_combination<F: FnMut(&[ExpansionNode])>(slots: Vec<Slot>, mut on_combination: F) {
+    let mut nodes: Vec<ExpansionNode> = slots.into_iter().map(ExpansionNode::from).collect();
 
     loop {
         if nodes[0].just_wrapped {
             break;
         }
 
+        on_combination(&nodes);
+
+        for idx in (0..nodes.len()).rev() {
+            let wrapped = nodes[idx].increment();
+
+            if !wrapped {
+                // Increment from right to left until something doesn't wrap
+                break;
+            }
+        }
+    }
+}
+
+fn print_expansions(writer: &mut dyn Write, number: &str, slots: Vec<Slot>) {
+    for_each_combination(slots, |nodes| {
         writer.write_all(number.as_bytes()).expect("IO error");
         writer.write_all(b":").expect("IO error");
 
-        for n in &nodes {
+        for n in nodes {
             writer.write_all(b" ").expect("IO error");
             writer.write_all(n.value().as_bytes()).expect("IO error");
         }
 
         writer.write_all(b"\n").expect("IO error");
+    });
+}
 
-        for idx in (0..nodes.len()).rev() {
-            let wrapped = nodes[idx].increment();
+// Escape a string as a quoted JSON string literal.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
 
-            if !wrapped {
-                // Increment from right to left until something doesn't wrap
-                break;
+    out.push('"');
+    out
+}
+
+// Render every combination of words across a set of slots as JSON-encoded
+// encodings, tagging each token as either a dictionary word (a JSON string)
+// or a literal digit (`{"digit":N}`).
+fn collect_encodings(slots: Vec<Slot>) -> Vec<String> {
+    let mut encodings = Vec::new();
+
+    for_each_combination(slots, |nodes| {
+        let mut encoding = String::from("[");
+
+        for (i, n) in nodes.iter().enumerate() {
+            if i > 0 {
+                encoding.push(',');
+            }
+
+            match n.digit {
+                Some(d) => encoding.push_str(&format!("{{\"digit\":{}}}", d)),
+                None => encoding.push_str(&json_escape(n.value())),
             }
         }
+
+        encoding.push(']');
+        encodings.push(encoding);
+    });
+
+    encodings
+}
+
+// Emit one newline-delimited JSON object for a number and all its encodings:
+// `{"number":"...","encodings":[[...],...]}`.
+fn print_expansions_json(writer: &mut dyn Write, number: &str, encodings: &[String]) {
+    writeln!(
+        writer,
+        "{{\"number\":{},\"encodings\":[{}]}}",
+        json_escape(number),
+        encodings.join(",")
+    )
+    .expect("IO error");
+}
+
+// State for one level of the backtracking search: the word-scan loop over
+// `number_digits[start..]` resumes at `idx` with `key` already folded from
+// `number_digits[start..idx - 1]`.
+#[derive(Clone)]
+struct Frame {
+    start: usize,
+    idx: usize,
+    key: WordKey,
+    found_word: bool,
+    literal_tried: bool,
+}
+
+impl Frame {
+    fn new(start: usize) -> Frame {
+        Frame {
+            start,
+            idx: start + 1,
+            key: WordKey::default(),
+            found_word: false,
+            literal_tried: false,
+        }
     }
 }
 
-struct MatchGenerator<'a> {
-    number_digits: &'a [u8],
-    dictionary: &'a Dictionary,
-    candidates: VecDeque<Candidate>,
+// A unit of work on the explicit search stack: either resume a frame, or pop
+// the `path` entry a frame pushed before descending into a child frame.
+enum StackItem {
+    Enter(Frame),
+    Pop,
 }
 
-impl<'a> MatchGenerator<'a> {
-    fn new(number_digits: &'a [u8], dictionary: &'a Dictionary) -> MatchGenerator<'a> {
-        let mut result = MatchGenerator {
-            number_digits,
-            dictionary,
-            candidates: VecDeque::new(),
+// Depth-first backtracking search over the input digits, sharing a single
+// `path` buffer across the whole search instead of cloning it per branch.
+// At `start` we scan forward extending the dictionary key digit-by-digit; for
+// every word found we push a Position slot and descend, then pop it again
+// once that subtree is exhausted. If no word matched at `start` and the
+// previous slot wasn't a literal, we also try a single literal digit.
+// `on_match` is called with the completed path whenever we reach the end of
+// the input, so both the printing path and the count-only mode can drive the
+// same search.
+//
+// This is driven by an explicit, heap-allocated stack rather than the native
+// call stack, so encoding length isn't bounded by thread stack size (a
+// recursive version overflows the stack on inputs with tens of thousands of
+// digits).
+fn search<F: FnMut(&[PositionOrLiteral])>(number_digits: &[u8], dictionary: &Dictionary, on_match: &mut F) {
+    let mut path: Vec<PositionOrLiteral> = Vec::new();
+    let mut stack: Vec<StackItem> = vec![StackItem::Enter(Frame::new(0))];
+
+    while let Some(item) = stack.pop() {
+        let mut frame = match item {
+            StackItem::Pop => {
+                path.pop();
+                continue;
+            }
+            StackItem::Enter(frame) => frame,
         };
 
-        // Each candidate represents a portion of the input digits that we haven't
-        // finished exploring.
-        result.candidates.push_back(Candidate {
-            input_position: 0,
-            word_end_positions_found: Vec::new(),
-        });
+        if frame.start == number_digits.len() {
+            on_match(&path);
+            continue;
+        }
 
-        result
-    }
-}
+        let start = frame.start;
+        let mut descended = false;
 
-impl<'a> Iterator for MatchGenerator<'a> {
-    type Item = Candidate;
-
-    fn next(&mut self) -> Option<Candidate> {
-        while let Some(candidate) = self.candidates.pop_back() {
-            let start_idx = candidate.input_position;
-
-            let mut found_word = false;
-
-            // Scan the rest of the input for this candidate.  As we find words in our
-            // dictionary, record their end positions and add new Candidates to our search
-            // list.
-            for idx in (candidate.input_position + 1)..=self.number_digits.len() {
-                let candidate_key: u128 = self.number_digits[start_idx..idx].iter().fold(0u128, |acc, &n| (acc * 10) + (n as u128));
-
-                if let Some(_words) = self.dictionary.get(&candidate_key) {
-                    // matched a word
-                    found_word = true;
-
-                    let mut positions = candidate.word_end_positions_found.clone();
-                    positions.push(PositionOrLiteral::Position(idx));
-
-                    let next_candidate = Candidate {
-                        input_position: idx,
-                        word_end_positions_found: positions,
-                        ..candidate
-                    };
-
-                    if idx == self.number_digits.len() {
-                        // A complete match!
-                        return Some(next_candidate);
-                    } else {
-                        // Partial match... keep looking from here
-                        self.candidates.push_back(next_candidate);
-                    }
-                }
+        while frame.idx <= number_digits.len() {
+            let end = frame.idx;
+            frame.key = frame.key.push_digit(number_digits[end - 1]);
+
+            if dictionary.contains_key(&frame.key) {
+                frame.found_word = true;
+
+                path.push(PositionOrLiteral::Position(end));
+
+                let mut resumed = frame.clone();
+                resumed.idx = end + 1;
+
+                stack.push(StackItem::Enter(resumed));
+                stack.push(StackItem::Pop);
+                stack.push(StackItem::Enter(Frame::new(end)));
+
+                descended = true;
+                break;
             }
 
-            // If we didn't find a word at `input_position`, we can add a digit here if we
-            // didn't do that for the last position.
-            if !found_word {
-                let last_was_literal = matches!(candidate.word_end_positions_found.last(), Some(PositionOrLiteral::Literal(_)));
+            frame.idx += 1;
+        }
+
+        if descended {
+            continue;
+        }
 
-                if !last_was_literal {
-                    // We have the option of inserting a literal digit
-                    let mut positions = candidate.word_end_positions_found;
-                    positions.push(PositionOrLiteral::Literal(
-                        self.number_digits[candidate.input_position],
-                    ));
+        // If we didn't find a word at `start`, we can add a digit here if we
+        // didn't do that for the last position.
+        if !frame.literal_tried {
+            frame.literal_tried = true;
 
-                    let next_candidate = Candidate {
-                        input_position: candidate.input_position + 1,
-                        word_end_positions_found: positions,
-                        ..candidate
-                    };
-
-                    if (candidate.input_position + 1) == self.number_digits.len() {
-                        // A complete match!
-                        return Some(next_candidate);
-                    } else {
-                        // Partial match... keep looking from here
-                        self.candidates.push_back(next_candidate);
-                    }
-                }
+            let last_was_literal = matches!(path.last(), Some(PositionOrLiteral::Literal(_)));
+
+            if !frame.found_word && !last_was_literal {
+                path.push(PositionOrLiteral::Literal(number_digits[start]));
+
+                stack.push(StackItem::Pop);
+                stack.push(StackItem::Enter(Frame::new(start + 1)));
             }
         }
+    }
+}
 
-        None
+// Reconstruct the slots (dictionary word lists or literal digits) for a
+// completed path, for the printing and JSON output paths.
+fn build_slots(dictionary: &Dictionary, number_digits: &[u8], path: &[PositionOrLiteral]) -> Vec<Slot> {
+    let mut slots: Vec<Slot> = Vec::new();
+    let mut last_idx = 0;
+
+    for slot in path {
+        match slot {
+            PositionOrLiteral::Literal(l) => {
+                slots.push(Slot::Literal(*l));
+                last_idx += 1;
+            }
+            PositionOrLiteral::Position(idx) => {
+                let key = fold_key(number_digits[last_idx..*idx].iter().copied());
+
+                slots.push(Slot::Word(dictionary.get(&key).unwrap().clone()));
+                last_idx = *idx;
+            }
+        }
     }
+
+    slots
 }
 
+// Count the encodings represented by a completed path without enumerating
+// them: each Position slot contributes the number of dictionary words
+// available there, each Literal slot contributes a factor of 1.
+fn count_expansions(dictionary: &Dictionary, number_digits: &[u8], path: &[PositionOrLiteral]) -> u128 {
+    let mut last_idx = 0;
+    let mut count: u128 = 1;
+
+    for slot in path {
+        match slot {
+            PositionOrLiteral::Literal(_) => {
+                last_idx += 1;
+            }
+            PositionOrLiteral::Position(idx) => {
+                let key = fold_key(number_digits[last_idx..*idx].iter().copied());
+
+                count *= dictionary.get(&key).unwrap().len() as u128;
+                last_idx = *idx;
+            }
+        }
+    }
+
+    count
+}
 
 fn main() {
     let mut args: Vec<_> = args().skip(1).collect();
+
+    let count_mode = if let Some(idx) = args.iter().position(|a| a == "--count") {
+        args.remove(idx);
+        true
+    } else {
+        false
+    };
+
+    let json_mode = if let Some(idx) = args.iter().position(|a| a == "--json") {
+        args.remove(idx);
+        true
+    } else {
+        false
+    };
+
+    let mapping_table = if let Some(idx) = args.iter().position(|a| a == "--mapping") {
+        args.remove(idx);
+
+        if idx >= args.len() {
+            panic!("--mapping requires a file path");
+        }
+
+        load_mapping_table(&args.remove(idx))
+    } else {
+        MappingTable::default_table()
+    };
+
     let words_file: String = if !args.is_empty() {
         args.remove(0)
     } else {
@@ -236,7 +510,7 @@ fn main() {
         panic!("need a numbers file")
     };
 
-    let dictionary = load_dictionary(&words_file);
+    let dictionary = load_dictionary(&words_file, &mapping_table);
 
     for number in read_lines(&input_file) {
         let mut number_digits: Vec<u8> = Vec::with_capacity(32);
@@ -251,30 +525,35 @@ fn main() {
             continue;
         }
 
+        if count_mode {
+            let mut count: u128 = 0;
+
+            search(&number_digits, &dictionary, &mut |path| {
+                count += count_expansions(&dictionary, &number_digits, path);
+            });
+
+            println!("{}: {}", number, count);
+            continue;
+        }
+
         let stdout = io::stdout();
         let mut writer = BufWriter::new(stdout.lock());
 
-        for m in MatchGenerator::new(&number_digits, &dictionary) {
-            let mut words: Vec<Vec<String>> = Vec::new();
-
-            let mut last_idx = 0;
-            for idx in m.word_end_positions_found {
-                match idx {
-                    PositionOrLiteral::Literal(l) => {
-                        words.push(vec![l.to_string()]);
-                        last_idx += 1;
-                    }
-                    PositionOrLiteral::Position(idx) => {
-                        // let key = number_digits[last_idx..idx].to_vec();
-                        let key: u128 = number_digits[last_idx..idx].iter().fold(0u128, |acc, &n| (acc * 10) + (n as u128));
-
-                        words.push(dictionary.get(&key).unwrap().clone());
-                        last_idx = idx;
-                    }
-                }
-            }
+        if json_mode {
+            let mut encodings: Vec<String> = Vec::new();
 
-            print_expansions(&mut writer, &number, words);
+            search(&number_digits, &dictionary, &mut |path| {
+                let slots = build_slots(&dictionary, &number_digits, path);
+                encodings.extend(collect_encodings(slots));
+            });
+
+            print_expansions_json(&mut writer, &number, &encodings);
+            continue;
         }
+
+        search(&number_digits, &dictionary, &mut |path| {
+            let slots = build_slots(&dictionary, &number_digits, path);
+            print_expansions(&mut writer, &number, slots);
+        });
     }
 }